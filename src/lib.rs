@@ -2,12 +2,10 @@
 //!
 //! # Example
 //!
-//! ```
-//! // Plug in the allocator crate
-//! extern crate alloc_cortex_m;
-//! extern crate collections;
+//! ```ignore
+//! extern crate alloc;
 //!
-//! use collections::Vec;
+//! use alloc::vec::Vec;
 //!
 //! // These symbols come from a linker script
 //! extern "C" {
@@ -15,14 +13,22 @@
 //!     static mut _heap_end: usize;
 //! }
 //!
+//! #[global_allocator]
+//! static ALLOCATOR: alloc_cortex_m::CortexMHeap = alloc_cortex_m::CortexMHeap::empty();
+//!
 //! #[no_mangle]
 //! pub fn main() -> ! {
 //!     // Initialize the heap BEFORE you use the allocator
-//!     unsafe { alloc_cortex_m::init(&mut _heap_start, &mut _heap_end) }
+//!     unsafe {
+//!         let start = &_heap_start as *const usize as usize;
+//!         let end = &_heap_end as *const usize as usize;
+//!         ALLOCATOR.init(start, end)
+//!     }
 //!
 //!     let mut xs = Vec::new();
 //!     xs.push(1);
 //!     // ...
+//!     loop {}
 //! }
 //! ```
 //!
@@ -36,22 +42,62 @@
 //! _heap_start = .;
 //! _heap_end = ORIGIN(SRAM) + LENGTH(SRAM) - _stack_size;
 //! ```
+//!
+//! # Critical sections
+//!
+//! The heap is guarded by [`critical_section::with`] rather than by
+//! `cortex_m::interrupt::Mutex` directly, so the allocator no longer assumes a
+//! single-core Cortex-M target with `cpsid i`/`cpsie i` as its only possible
+//! critical-section implementation. On the common single-core case, enable
+//! the `critical-section-single-core` feature on `cortex-m` (which registers
+//! a `critical-section` impl backed by masking interrupts) in your own
+//! `Cargo.toml`. On multicore or non-Cortex-M targets, provide a
+//! `critical-section::Impl` appropriate for that platform instead.
+//!
+//! # `#[global_allocator]`
+//!
+//! `CortexMHeap` implements `core::alloc::GlobalAlloc`, so it can be
+//! registered directly:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: alloc_cortex_m::CortexMHeap = alloc_cortex_m::CortexMHeap::empty();
+//! ```
 
-#![feature(const_fn)]
 #![no_std]
-#![feature(alloc, allocator_api)]
 
-extern crate cortex_m;
+extern crate critical_section;
 extern crate linked_list_allocator;
-extern crate alloc;
-
-use alloc::allocator::{Alloc, Layout, AllocErr};
 
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+use critical_section::Mutex;
 use linked_list_allocator::Heap;
-use cortex_m::interrupt::Mutex;
+
+mod multicore;
+mod slab;
+
+pub use multicore::MultiCoreHeap;
+pub use slab::SlabHeap;
+
+/// A user-registered out-of-memory hook.
+///
+/// Called from `alloc` when `allocate_first_fit` fails, with the `Layout`
+/// that couldn't be satisfied and the heap's `used`/`free` watermarks at the
+/// time of failure, so firmware can log the shortfall or trigger a
+/// controlled reset instead of silently getting a null pointer back.
+///
+/// The handler is invoked with the heap's internal lock already released, so
+/// it may call `used()`/`free()`/`size()` (or allocate) without deadlocking
+/// or double-borrowing — but note that an allocating handler that itself
+/// runs out of memory will recurse into this same hook.
+pub type OomHandler = fn(layout: &Layout, used: usize, free: usize);
 
 pub struct CortexMHeap {
-    heap: Mutex<Heap>,
+    heap: Mutex<RefCell<Heap>>,
+    oom_handler: Mutex<RefCell<Option<OomHandler>>>,
 }
 
 impl CortexMHeap {
@@ -60,12 +106,42 @@ impl CortexMHeap {
     ///
     /// You must initialize this heap using the
     /// [`init`](struct.CortexMHeap.html#method.init) method before using the allocator.
-    pub unsafe fn empty() -> CortexMHeap {
+    pub const fn empty() -> CortexMHeap {
         CortexMHeap {
-            heap: Mutex::new(Heap::empty()),
+            heap: Mutex::new(RefCell::new(Heap::empty())),
+            oom_handler: Mutex::new(RefCell::new(None)),
         }
     }
 
+    /// Registers a callback invoked when `alloc` fails to satisfy a request.
+    ///
+    /// Replaces any previously registered handler.
+    pub fn register_oom_handler(&self, handler: OomHandler) {
+        critical_section::with(|cs| *self.oom_handler.borrow(cs).borrow_mut() = Some(handler));
+    }
+
+    /// Returns the number of bytes currently allocated out of the heap.
+    ///
+    /// Forwards to `linked_list_allocator::Heap::used`, which derives the
+    /// figure from the heap's own bottom/top/hole-list bookkeeping; this
+    /// requires `linked_list_allocator` >= 0.9 (see `Cargo.toml`).
+    pub fn used(&self) -> usize {
+        critical_section::with(|cs| self.heap.borrow(cs).borrow().used())
+    }
+
+    /// Returns the number of bytes still available to satisfy allocations.
+    pub fn free(&self) -> usize {
+        critical_section::with(|cs| self.heap.borrow(cs).borrow().free())
+    }
+
+    /// Returns the total size of the heap, i.e. `used() + free()`.
+    pub fn size(&self) -> usize {
+        critical_section::with(|cs| {
+            let heap = self.heap.borrow(cs).borrow();
+            heap.used() + heap.free()
+        })
+    }
+
     /// Initializes the heap
     ///
     /// This function must be called BEFORE you run any code that makes use of the
@@ -83,26 +159,150 @@ impl CortexMHeap {
     /// - The size of the heap is `(end_addr as usize) - (start_addr as usize)`. The
     ///   allocator won't use the byte at `end_addr`.
     ///
-    /// # Unsafety
+    /// # Safety
     ///
     /// Obey these or Bad Stuff will happen.
     ///
     /// - This function must be called exactly ONCE.
     /// - `end_addr` > `start_addr`
-    pub unsafe fn init(&self, start_addr: usize, end_addr: usize){
+    pub unsafe fn init(&self, start_addr: usize, end_addr: usize) {
         let size = end_addr - start_addr;
-        self.heap.lock(|heap| heap.init(start_addr, size));
+        critical_section::with(|cs| {
+            self.heap.borrow(cs).borrow_mut().init(start_addr as *mut u8, size)
+        });
+    }
+
+    /// Initializes the heap from a `'static` slice of memory.
+    ///
+    /// Unlike [`init`](struct.CortexMHeap.html#method.init), the start
+    /// address and size are derived from `mem` itself, so there's no
+    /// end-address arithmetic for callers to get wrong: a plain
+    /// `static mut HEAP_MEM: [MaybeUninit<u8>; N]` backs the heap directly,
+    /// and `'static` plus `&mut` guarantee the region is valid and uniquely
+    /// borrowed for as long as the allocator uses it.
+    ///
+    /// # Safety
+    ///
+    /// - This function must be called exactly ONCE.
+    pub unsafe fn init_from_slice(&self, mem: &'static mut [MaybeUninit<u8>]) {
+        let size = mem.len();
+        let start_addr = mem.as_mut_ptr() as usize;
+        self.init(start_addr, start_addr + size);
     }
 }
 
-unsafe impl<'a> Alloc for &'a CortexMHeap {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        self.heap.lock(|heap| {
-            heap.allocate_first_fit(layout)
+unsafe impl GlobalAlloc for CortexMHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        critical_section::with(|cs| {
+            let mut heap = self.heap.borrow(cs).borrow_mut();
+            match heap.allocate_first_fit(layout) {
+                Ok(p) => p.as_ptr(),
+                Err(()) => {
+                    let used = heap.used();
+                    let free = heap.free();
+                    drop(heap);
+                    if let Some(handler) = *self.oom_handler.borrow(cs).borrow() {
+                        handler(&layout, used, free);
+                    }
+                    ptr::null_mut()
+                }
+            }
         })
     }
 
-    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        self.heap.lock(|heap| heap.deallocate(ptr, layout));
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new_unchecked(ptr);
+        critical_section::with(|cs| self.heap.borrow(cs).borrow_mut().deallocate(ptr, layout));
+    }
+
+    // No `realloc` override: `linked_list_allocator::Heap` has no API to
+    // extend or partially free a *live* allocation in place — `Heap::extend`
+    // only grows the heap's total managed region (adding a brand new free
+    // hole at the old top), it doesn't enlarge an existing block, and
+    // freeing an interior sub-slice isn't a supported operation either. A
+    // custom override could therefore only match `GlobalAlloc::realloc`'s
+    // default behavior (alloc new layout, copy, free the old one under its
+    // *original* layout) on both grow and shrink, so we just inherit it.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::MaybeUninit;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    unsafe fn new_heap(mem: &mut [MaybeUninit<u8>]) -> CortexMHeap {
+        let heap = CortexMHeap::empty();
+        let start = mem.as_mut_ptr() as usize;
+        heap.init(start, start + mem.len());
+        heap
+    }
+
+    static OOM_TEST_HEAP: CortexMHeap = CortexMHeap::empty();
+    static OOM_FIRED: AtomicBool = AtomicBool::new(false);
+
+    fn oom_probe_handler(_layout: &Layout, _used: usize, _free: usize) {
+        // If `alloc` invoked this handler while still holding the heap's
+        // `RefCell` borrow, either of these would panic with "already
+        // borrowed" instead of returning a value.
+        let _ = OOM_TEST_HEAP.used();
+        let _ = OOM_TEST_HEAP.free();
+        OOM_FIRED.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn oom_handler_can_query_heap_without_double_borrow_panic() {
+        let mut mem = [MaybeUninit::<u8>::uninit(); 64];
+        let start = mem.as_mut_ptr() as usize;
+        unsafe { OOM_TEST_HEAP.init(start, start + mem.len()) };
+        OOM_TEST_HEAP.register_oom_handler(oom_probe_handler);
+
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let p = unsafe { OOM_TEST_HEAP.alloc(layout) };
+        assert!(p.is_null());
+        assert!(OOM_FIRED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn realloc_grow_copies_bytes_and_frees_original_block() {
+        let mut mem = [MaybeUninit::<u8>::uninit(); 4096];
+        let heap = unsafe { new_heap(&mut mem) };
+
+        let old_layout = Layout::from_size_align(32, 8).unwrap();
+        let p = unsafe { heap.alloc(old_layout) };
+        assert!(!p.is_null());
+        unsafe { ptr::write_bytes(p, 0xAB, 32) };
+
+        let grown = unsafe { heap.realloc(p, old_layout, 128) };
+        assert!(!grown.is_null());
+        let bytes = unsafe { core::slice::from_raw_parts(grown, 32) };
+        assert!(bytes.iter().all(|&b| b == 0xAB));
+
+        let grown_layout = Layout::from_size_align(128, 8).unwrap();
+        unsafe { heap.dealloc(grown, grown_layout) };
+
+        // The original 32-byte block must have been freed under its own
+        // layout, not left dangling.
+        assert_eq!(heap.used(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn realloc_shrink_does_not_leak_the_freed_tail() {
+        let mut mem = [MaybeUninit::<u8>::uninit(); 4096];
+        let heap = unsafe { new_heap(&mut mem) };
+
+        let old_layout = Layout::from_size_align(128, 8).unwrap();
+        let p = unsafe { heap.alloc(old_layout) };
+        assert!(!p.is_null());
+
+        let shrunk = unsafe { heap.realloc(p, old_layout, 32) };
+        assert!(!shrunk.is_null());
+
+        let shrunk_layout = Layout::from_size_align(32, 8).unwrap();
+        unsafe { heap.dealloc(shrunk, shrunk_layout) };
+
+        // A leaked tail would show up as `used() > 0` here: the whole
+        // original block must come back, not just the smaller new layout.
+        assert_eq!(heap.used(), 0);
+    }
+}
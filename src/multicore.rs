@@ -0,0 +1,169 @@
+//! A per-core heap for multicore Cortex-M targets
+//!
+//! A single heap shared by every core needs a critical section around every
+//! allocation just to serialize access between cores, even though each core
+//! usually only ever touches its own objects. [`MultiCoreHeap`] instead hands
+//! each core a disjoint region of RAM and its own [`Heap`]; `alloc`/`dealloc`
+//! dispatch to the current core's heap, so the fast path never has to
+//! contend with another core and only needs the same local interrupt masking
+//! a single-core heap would use. This mirrors the split-per-core allocator
+//! pattern used on Cortex-A9/Zynq.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ptr::NonNull;
+
+use critical_section::Mutex;
+use linked_list_allocator::Heap;
+
+/// Maximum number of cores supported by a [`MultiCoreHeap`].
+///
+/// This covers the dual-core Cortex-M0+/M33 parts (e.g. the RP2040) this
+/// mode targets. Raising it also means adding entries to the array literal
+/// in `MultiCoreHeap::empty` (and updating the check below); since `heaps`
+/// is typed `[_; MAX_CORES]`, a literal of any other length fails to compile.
+pub const MAX_CORES: usize = 2;
+
+// `MultiCoreHeap::empty`'s array literal below isn't derived from
+// `MAX_CORES` (const generics aren't used, to keep `empty` a `const fn`
+// without requiring `Heap: Copy`), so it has to be edited by hand whenever
+// `MAX_CORES` changes. This constant spells out the literal's length (2)
+// next to `MAX_CORES` and fails to compile as soon as the two differ.
+const _CHECK_ARRAY_LITERAL_MATCHES_MAX_CORES: [(); MAX_CORES] = [(); 2];
+
+/// A heap allocator with one independent [`Heap`] per core.
+///
+/// Each core's heap is still wrapped in a `critical_section::Mutex` for
+/// local interrupt-safety (an allocation can be interrupted by an ISR on the
+/// same core), but because no two cores ever touch the same slot, no
+/// cross-core contention occurs and a single-core-local critical-section
+/// implementation is enough even when running on multicore hardware.
+pub struct MultiCoreHeap {
+    heaps: [Mutex<RefCell<Heap>>; MAX_CORES],
+    core_id: fn() -> usize,
+}
+
+impl MultiCoreHeap {
+    /// Creates a new UNINITIALIZED per-core heap.
+    ///
+    /// `core_id` is called on every `alloc`/`dealloc` to read the current
+    /// core's id, typically from an `MPIDR`-style register. An out-of-range
+    /// return value (`>= MAX_CORES`) is handled gracefully rather than
+    /// trusted blindly — see
+    /// [`current_core`](struct.MultiCoreHeap.html#method.current_core).
+    ///
+    /// Each core's heap is UNINITIALIZED; call
+    /// [`init_core`](struct.MultiCoreHeap.html#method.init_core) for every
+    /// core before it allocates.
+    pub const fn empty(core_id: fn() -> usize) -> MultiCoreHeap {
+        MultiCoreHeap {
+            heaps: [
+                Mutex::new(RefCell::new(Heap::empty())),
+                Mutex::new(RefCell::new(Heap::empty())),
+            ],
+            core_id,
+        }
+    }
+
+    /// Initializes the heap slice belonging to `core`.
+    ///
+    /// `start_addr` and `size` must describe a region disjoint from every
+    /// other core's region.
+    ///
+    /// # Safety
+    ///
+    /// - This function must be called exactly ONCE per core, before that
+    ///   core allocates.
+    /// - `core` must be `< MAX_CORES`.
+    pub unsafe fn init_core(&self, core: usize, start_addr: usize, size: usize) {
+        critical_section::with(|cs| {
+            self.heaps[core].borrow(cs).borrow_mut().init(start_addr as *mut u8, size)
+        });
+    }
+
+    /// Returns the current core's id, if `core_id()` reported one that's
+    /// actually backed by a heap slot.
+    ///
+    /// A misbehaving `core_id` closure, or a target whose real core count
+    /// has drifted past `MAX_CORES`, must not panic the allocation fast path
+    /// by indexing `heaps` out of bounds — so callers check this before
+    /// indexing rather than indexing directly.
+    fn current_core(&self) -> Option<usize> {
+        let core = (self.core_id)();
+        if core < MAX_CORES {
+            Some(core)
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for MultiCoreHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let core = match self.current_core() {
+            Some(core) => core,
+            None => return core::ptr::null_mut(),
+        };
+        critical_section::with(|cs| {
+            self.heaps[core]
+                .borrow(cs)
+                .borrow_mut()
+                .allocate_first_fit(layout)
+                .map_or(core::ptr::null_mut(), |p| p.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // `ptr` was necessarily handed out by `alloc` above through a valid
+        // core id, so a `None` here means `core_id` changed its answer in
+        // between — there's no slot left to safely return the block to.
+        let core = match self.current_core() {
+            Some(core) => core,
+            None => return,
+        };
+        let ptr = NonNull::new_unchecked(ptr);
+        critical_section::with(|cs| self.heaps[core].borrow(cs).borrow_mut().deallocate(ptr, layout));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::MaybeUninit;
+
+    fn out_of_range_core() -> usize {
+        MAX_CORES
+    }
+
+    #[test]
+    fn out_of_range_core_id_returns_null_instead_of_panicking() {
+        let heap = MultiCoreHeap::empty(out_of_range_core);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        // Must not index `heaps` out of bounds; a null return is the
+        // documented, non-panicking behavior for a misbehaving `core_id`.
+        let p = unsafe { heap.alloc(layout) };
+        assert!(p.is_null());
+
+        // Must also not panic when asked to free a pointer under the same
+        // out-of-range core id.
+        unsafe { heap.dealloc(core::ptr::dangling_mut::<u8>(), layout) };
+    }
+
+    #[test]
+    fn in_range_core_allocates_from_its_own_slot() {
+        fn core_zero() -> usize {
+            0
+        }
+
+        let heap = MultiCoreHeap::empty(core_zero);
+        let mut mem = [MaybeUninit::<u8>::uninit(); 4 * 1024];
+        let start = mem.as_mut_ptr() as usize;
+        unsafe { heap.init_core(0, start, mem.len()) };
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { heap.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { heap.dealloc(p, layout) };
+    }
+}
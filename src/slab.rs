@@ -0,0 +1,246 @@
+//! A slab allocator for small, fixed-size objects
+//!
+//! [`SlabHeap`] keeps one free list per size class and only falls back to the
+//! general-purpose [`linked_list_allocator::Heap`] (first-fit, O(n)) for
+//! requests that don't fit any class or for growing a class that has run out
+//! of blocks. This trades the fragmentation and search cost of first-fit for
+//! the common case of many same-sized allocations, at the price of wasting
+//! the gap between a request's size and its class's size.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ptr::NonNull;
+
+use critical_section::Mutex;
+use linked_list_allocator::Heap;
+
+/// The size, in bytes, of each slab class, smallest first.
+const SIZE_CLASSES: [usize; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Number of blocks pulled from the fallback allocator at once when a class
+/// runs dry, so refills are amortized instead of happening one block at a
+/// time.
+const REFILL_COUNT: usize = 4;
+
+/// Upper bound, in bytes, on the contiguous chunk `refill_class` asks the
+/// fallback allocator for in one go.
+///
+/// Applying `REFILL_COUNT` uniformly to every class would make refilling the
+/// 4096-byte class request a 16384-byte contiguous chunk, which can fail on a
+/// modest heap even when a single 4096-byte block is available -- a
+/// premature OOM for large classes that never needed more than one block.
+/// Capping the chunk means large classes refill fewer blocks at a time
+/// (still at least one), while small classes keep the full amortized
+/// `REFILL_COUNT`.
+const REFILL_CHUNK_CAP: usize = 4 * SIZE_CLASSES[0];
+
+/// Returns the number of `block_size` blocks `refill_class` should request in
+/// one chunk, at least 1 and at most `REFILL_COUNT`.
+const fn refill_count_for(block_size: usize) -> usize {
+    let capped = REFILL_CHUNK_CAP / block_size;
+    if capped == 0 {
+        1
+    } else if capped < REFILL_COUNT {
+        capped
+    } else {
+        REFILL_COUNT
+    }
+}
+
+/// Returns the index of the smallest size class that fits `layout`, or
+/// `None` if no class is both large enough and sufficiently aligned (every
+/// class is a power of two, so a class that fits the size also satisfies any
+/// alignment up to its own size).
+fn class_for(layout: &Layout) -> Option<usize> {
+    let needed = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= needed)
+}
+
+/// An intrusive free-list node. Stored inside the free block it describes, so
+/// free slabs need no metadata memory of their own.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+struct Slabs {
+    free_lists: [Option<NonNull<FreeBlock>>; SIZE_CLASSES.len()],
+    fallback: Heap,
+}
+
+impl Slabs {
+    const fn empty() -> Slabs {
+        Slabs {
+            free_lists: [None; SIZE_CLASSES.len()],
+            fallback: Heap::empty(),
+        }
+    }
+
+    /// Pops a block off `free_lists[class]`, refilling from the fallback
+    /// allocator first if the list is empty.
+    unsafe fn alloc_class(&mut self, class: usize) -> Result<NonNull<u8>, ()> {
+        if self.free_lists[class].is_none() {
+            self.refill_class(class)?;
+        }
+
+        let block = self.free_lists[class].unwrap();
+        self.free_lists[class] = block.as_ref().next;
+        Ok(block.cast())
+    }
+
+    /// Requests a multi-block chunk from the fallback allocator (sized via
+    /// [`refill_count_for`] so large classes don't demand an oversized
+    /// contiguous chunk) and splits it into equal-sized blocks on
+    /// `free_lists[class]`. Falls back to a single block if even that
+    /// smaller chunk can't be satisfied.
+    unsafe fn refill_class(&mut self, class: usize) -> Result<(), ()> {
+        let block_size = SIZE_CLASSES[class];
+        let count = refill_count_for(block_size);
+
+        let (chunk, count) = match self.refill_chunk(block_size, count) {
+            Ok(chunk) => (chunk, count),
+            Err(()) if count > 1 => (self.refill_chunk(block_size, 1)?, 1),
+            Err(()) => return Err(()),
+        };
+
+        for i in 0..count {
+            let block = chunk.as_ptr().add(i * block_size) as *mut FreeBlock;
+            (*block).next = self.free_lists[class];
+            self.free_lists[class] = Some(NonNull::new_unchecked(block));
+        }
+
+        Ok(())
+    }
+
+    /// Requests a `count`-block contiguous chunk of `block_size`-byte blocks
+    /// from the fallback allocator. Split out of `refill_class` so it can be
+    /// retried with a smaller `count` on failure.
+    unsafe fn refill_chunk(&mut self, block_size: usize, count: usize) -> Result<NonNull<u8>, ()> {
+        let chunk_layout =
+            Layout::from_size_align(block_size * count, block_size).map_err(|_| ())?;
+        self.fallback.allocate_first_fit(chunk_layout)
+    }
+
+    unsafe fn dealloc_class(&mut self, ptr: NonNull<u8>, class: usize) {
+        let block = ptr.as_ptr() as *mut FreeBlock;
+        (*block).next = self.free_lists[class];
+        self.free_lists[class] = Some(NonNull::new_unchecked(block));
+    }
+}
+
+// `Slabs` is only ever touched from inside `critical_section::with`, so the
+// raw pointers in its free lists never see concurrent access.
+unsafe impl Send for Slabs {}
+
+/// A heap allocator that serves small allocations from per-size-class free
+/// lists and falls back to first-fit for anything larger than the biggest
+/// class (4096 bytes).
+pub struct SlabHeap {
+    slabs: Mutex<RefCell<Slabs>>,
+}
+
+impl SlabHeap {
+    /// Creates a new UNINITIALIZED slab heap.
+    ///
+    /// You must initialize this heap using the
+    /// [`init`](struct.SlabHeap.html#method.init) method before using the allocator.
+    pub const fn empty() -> SlabHeap {
+        SlabHeap {
+            slabs: Mutex::new(RefCell::new(Slabs::empty())),
+        }
+    }
+
+    /// Initializes the heap.
+    ///
+    /// # Safety
+    ///
+    /// See [`CortexMHeap::init`](struct.CortexMHeap.html#method.init); the
+    /// requirements are identical here, since the region backs the same kind
+    /// of fallback `Heap`.
+    pub unsafe fn init(&self, start_addr: usize, end_addr: usize) {
+        let size = end_addr - start_addr;
+        critical_section::with(|cs| {
+            self.slabs.borrow(cs).borrow_mut().fallback.init(start_addr as *mut u8, size)
+        });
+    }
+}
+
+unsafe impl GlobalAlloc for SlabHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        critical_section::with(|cs| {
+            let mut slabs = self.slabs.borrow(cs).borrow_mut();
+            let result = match class_for(&layout) {
+                Some(class) => slabs.alloc_class(class),
+                None => slabs.fallback.allocate_first_fit(layout),
+            };
+            result.map_or(core::ptr::null_mut(), |p| p.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new_unchecked(ptr);
+        critical_section::with(|cs| {
+            let mut slabs = self.slabs.borrow(cs).borrow_mut();
+            match class_for(&layout) {
+                Some(class) => slabs.dealloc_class(ptr, class),
+                None => slabs.fallback.deallocate(ptr, layout),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::MaybeUninit;
+
+    unsafe fn new_heap(mem: &mut [MaybeUninit<u8>]) -> SlabHeap {
+        let heap = SlabHeap::empty();
+        let start = mem.as_mut_ptr() as usize;
+        heap.init(start, start + mem.len());
+        heap
+    }
+
+    #[test]
+    fn alloc_dealloc_round_trip_reuses_freed_block() {
+        let mut mem = [MaybeUninit::<u8>::uninit(); 16 * 1024];
+        let heap = unsafe { new_heap(&mut mem) };
+
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let a = unsafe { heap.alloc(layout) };
+        assert!(!a.is_null());
+        unsafe { heap.dealloc(a, layout) };
+
+        // A block freed back to its class's free list should be handed
+        // straight back out, not re-pulled from the fallback allocator.
+        let b = unsafe { heap.alloc(layout) };
+        assert_eq!(a, b);
+        unsafe { heap.dealloc(b, layout) };
+    }
+
+    #[test]
+    fn oversized_request_falls_back_to_first_fit() {
+        let mut mem = [MaybeUninit::<u8>::uninit(); 16 * 1024];
+        let heap = unsafe { new_heap(&mut mem) };
+
+        let layout = Layout::from_size_align(8192, 8).unwrap();
+        let p = unsafe { heap.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { heap.dealloc(p, layout) };
+    }
+
+    #[test]
+    fn large_class_refill_does_not_spuriously_oom_on_a_small_heap() {
+        // Before capping the refill chunk, refilling the 4096-byte class
+        // always asked the fallback allocator for a REFILL_COUNT*4096 = 16KiB
+        // contiguous chunk, which this 12KiB heap could never satisfy even
+        // though a single 4096-byte, 4096-aligned block fits in it wherever
+        // the buffer happens to start.
+        let mut mem = [MaybeUninit::<u8>::uninit(); 12 * 1024];
+        let heap = unsafe { new_heap(&mut mem) };
+
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let p = unsafe { heap.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { heap.dealloc(p, layout) };
+    }
+}